@@ -7,6 +7,23 @@ use std::path::{Path, PathBuf};
 use std::process::Command;
 use structopt::{clap::AppSettings, StructOpt};
 
+/// Long flags recognized by [`Cli`]. `TrailingVarArg` means any bare word on the command
+/// line (such as a stray second `--features` value) makes everything after it, flags
+/// included, land in [`Cli::args`] instead of being parsed — see
+/// [`check_for_misrouted_flags`].
+const KNOWN_FLAGS: &[&str] = &[
+    "--dest",
+    "--manifest-path",
+    "--features",
+    "--no-default-features",
+    "--all-features",
+    "--dry-run",
+    "--locked",
+    "--offline",
+    "--lockfile",
+    "--update",
+];
+
 #[derive(StructOpt)]
 #[structopt(
     bin_name = "cargo prepare",
@@ -18,6 +35,48 @@ struct Cli {
     #[structopt(long = "dest", short = "o", conflicts_with = "args")]
     destination: Option<PathBuf>,
 
+    /// Path to the Cargo.toml to prepare. Defaults to the manifest in the current directory.
+    #[structopt(long = "manifest-path")]
+    manifest_path: Option<PathBuf>,
+
+    /// Comma separated list of features to activate. Pass `--features` multiple times to
+    /// activate features from more than one set.
+    #[structopt(long = "features", number_of_values = 1)]
+    features: Vec<String>,
+
+    /// Do not activate the `default` feature.
+    #[structopt(long = "no-default-features")]
+    no_default_features: bool,
+
+    /// Activate all available features.
+    #[structopt(long = "all-features")]
+    all_features: bool,
+
+    /// Print the files that would be created in the fake workspace without touching the
+    /// filesystem.
+    #[structopt(long = "dry-run")]
+    dry_run: bool,
+
+    /// Require that Cargo.lock stays unchanged, both for metadata resolution and for the
+    /// cached cargo command.
+    #[structopt(long = "locked")]
+    locked: bool,
+
+    /// Run without accessing the network, both for metadata resolution and for the cached
+    /// cargo command.
+    #[structopt(long = "offline")]
+    offline: bool,
+
+    /// Path to the Cargo.lock to copy into the fake workspace. Defaults to `Cargo.lock` in the
+    /// workspace root.
+    #[structopt(long = "lockfile")]
+    lockfile: Option<PathBuf>,
+
+    /// Run `cargo update` inside the fake workspace after it is created, to repin dependencies
+    /// before the cached build.
+    #[structopt(long = "update")]
+    update: bool,
+
     /// Rest of the arguments passed to cargo if destination is not specified.
     args: Vec<String>,
 }
@@ -31,27 +90,82 @@ fn main() -> Result<()> {
         args.next();
     }
     let cli = Cli::from_iter(command.into_iter().chain(args));
+    check_for_misrouted_flags(&cli.args)?;
 
-    let metadata = MetadataCommand::new()
-        .features(CargoOpt::AllFeatures)
+    if cli.dry_run && cli.destination.is_none() {
+        bail!("--dry-run requires --dest, otherwise the printed paths would refer to a temporary directory that is deleted before this command returns");
+    }
+
+    let mut metadata_command = MetadataCommand::new();
+    if let Some(manifest_path) = cli.manifest_path.as_ref() {
+        metadata_command.manifest_path(manifest_path);
+    }
+    if cli.all_features {
+        metadata_command.features(CargoOpt::AllFeatures);
+    } else {
+        if cli.no_default_features {
+            metadata_command.features(CargoOpt::NoDefaultFeatures);
+        }
+        if !cli.features.is_empty() {
+            metadata_command.features(CargoOpt::SomeFeatures(cli.features.clone()));
+        }
+    }
+    let mut other_options = Vec::new();
+    if cli.locked {
+        other_options.push("--locked".to_string());
+    }
+    if cli.offline {
+        other_options.push("--offline".to_string());
+    }
+    if !other_options.is_empty() {
+        metadata_command.other_options(other_options);
+    }
+    let metadata = metadata_command
         .exec()
         .context("could not read cargo metadata")?;
 
+    let cargo = env::var("CARGO")
+        .map(PathBuf::from)
+        .unwrap_or_else(|_| PathBuf::from("cargo"));
+
     if let Some(destination) = cli.destination.as_ref() {
-        fs::create_dir(destination).context("could not create destination directory")?;
-        initialize_fake_workspace(&metadata, destination)?;
+        if !cli.dry_run {
+            fs::create_dir(destination).context("could not create destination directory")?;
+        }
+        initialize_fake_workspace(
+            &metadata,
+            destination,
+            cli.dry_run,
+            cli.lockfile.as_deref(),
+            cli.update,
+        )?;
+        if cli.update && !cli.dry_run {
+            run_cargo_update(&cargo, destination, cli.offline)?;
+        }
     } else {
         let dir = tempfile::tempdir().context("could not create temporary directory")?;
-        let cargo = env::var("CARGO")
-            .map(PathBuf::from)
-            .unwrap_or_else(|_| PathBuf::from("cargo"));
 
-        initialize_fake_workspace(&metadata, dir.path())?;
+        initialize_fake_workspace(
+            &metadata,
+            dir.path(),
+            cli.dry_run,
+            cli.lockfile.as_deref(),
+            cli.update,
+        )?;
+        if cli.update {
+            run_cargo_update(&cargo, dir.path(), cli.offline)?;
+        }
 
         let mut command = Command::new(&cargo);
         command.env("CARGO_TARGET_DIR", metadata.target_directory);
         command.current_dir(dir.path());
         command.args(cli.args);
+        if cli.locked {
+            command.arg("--locked");
+        }
+        if cli.offline {
+            command.arg("--offline");
+        }
         let status = command
             .status()
             .context("could not execute cargo command")?;
@@ -64,23 +178,110 @@ fn main() -> Result<()> {
     Ok(())
 }
 
-fn initialize_fake_workspace(metadata: &Metadata, destination: &Path) -> Result<()> {
-    let lock_file = metadata.workspace_root.join("Cargo.lock");
+/// Bails with a clear error if one of `args` looks like a cargo-prepare flag.
+///
+/// `Cli::args` is a `TrailingVarArg` catch-all meant for the real cargo command (e.g. the
+/// `build --release` in `cargo prepare --dest out build --release`): once the parser sees a
+/// bare word, everything after it, flag-shaped or not, is swept into `args` verbatim instead
+/// of being parsed. A stray bare word before a cargo-prepare flag (for example trying to pass
+/// `--features a b` as if it were space separated) therefore makes that flag silently become
+/// a pass-through cargo argument. Catch the common case here instead of letting it fail later
+/// with a confusing error from the nested `cargo` invocation.
+fn check_for_misrouted_flags(args: &[String]) -> Result<()> {
+    if let Some(flag) = args.iter().find(|arg| KNOWN_FLAGS.contains(&arg.as_str())) {
+        bail!(
+            "`{}` was parsed as a cargo argument instead of a cargo-prepare flag; make sure \
+             all cargo-prepare flags come before the cargo command you want to run",
+            flag
+        );
+    }
+
+    Ok(())
+}
+
+/// Runs `cargo update` inside the fake workspace at `destination`, so that the copied or
+/// missing `Cargo.lock` gets repinned before the cached build relies on it.
+fn run_cargo_update(cargo: &Path, destination: &Path, offline: bool) -> Result<()> {
+    let mut command = Command::new(cargo);
+    command.arg("update");
+    command.current_dir(destination);
+    if offline {
+        command.arg("--offline");
+    }
+    let status = command
+        .status()
+        .context("could not execute cargo update command")?;
+
+    if !status.success() {
+        bail!("cargo update command failed");
+    }
+
+    Ok(())
+}
+
+fn initialize_fake_workspace(
+    metadata: &Metadata,
+    destination: &Path,
+    dry_run: bool,
+    lockfile: Option<&Path>,
+    update: bool,
+) -> Result<()> {
+    let lock_file = match lockfile {
+        Some(lockfile) => lockfile.to_path_buf(),
+        None => metadata
+            .workspace_root
+            .join("Cargo.lock")
+            .into_std_path_buf(),
+    };
     let tmp_lock_file = destination.join("Cargo.lock");
-    fs::copy(&lock_file, &tmp_lock_file).with_context(|| {
-        format!(
-            "could not copy Cargo.lock file: `{}` to `{}`",
-            lock_file.display(),
-            tmp_lock_file.display()
-        )
-    })?;
+    if lock_file.exists() {
+        if dry_run {
+            println!(
+                "would copy Cargo.lock file: `{}` to `{}`",
+                lock_file.display(),
+                tmp_lock_file.display()
+            );
+        } else {
+            fs::copy(&lock_file, &tmp_lock_file).with_context(|| {
+                format!(
+                    "could not copy Cargo.lock file: `{}` to `{}`",
+                    lock_file.display(),
+                    tmp_lock_file.display()
+                )
+            })?;
+        }
+    } else if !update {
+        bail!(
+            "no Cargo.lock found at `{}`; pass --lockfile to point at an existing one or \
+             --update to let cargo generate it",
+            lock_file.display()
+        );
+    }
 
-    let members: HashSet<_> = metadata.workspace_members.iter().collect();
-    let members: Vec<_> = metadata
-        .packages
-        .iter()
-        .filter(|x| members.contains(&x.id))
-        .collect();
+    // When the manifest points at a standalone package (one whose own
+    // Cargo.toml has no `[workspace]` table), only that package needs to be
+    // prepared. A virtual workspace has no root package, and a root package
+    // that is itself the root of a (possibly mixed) workspace still wants
+    // every member prepared, so both fall back to the full member list.
+    let root = metadata
+        .resolve
+        .as_ref()
+        .and_then(|resolve| resolve.root.as_ref())
+        .and_then(|root| metadata.packages.iter().find(|package| &package.id == root));
+    let standalone_root = match root {
+        Some(root) if !manifest_declares_workspace(root.manifest_path.as_std_path())? => Some(root),
+        _ => None,
+    };
+    let members: Vec<_> = if let Some(root) = standalone_root {
+        vec![root]
+    } else {
+        let members: HashSet<_> = metadata.workspace_members.iter().collect();
+        metadata
+            .packages
+            .iter()
+            .filter(|x| members.contains(&x.id))
+            .collect()
+    };
 
     for member in members {
         let package_path = member.manifest_path.parent().unwrap();
@@ -91,36 +292,140 @@ fn initialize_fake_workspace(metadata: &Metadata, destination: &Path) -> Result<
         let tmp_manifest = destination.join(&relative_path);
         let tmp_package = tmp_manifest.parent().unwrap();
 
-        fs::create_dir_all(&tmp_package).with_context(|| {
-            format!(
-                "could not create package directory: `{}`",
+        if dry_run {
+            println!(
+                "would create package directory: `{}`",
                 tmp_package.display()
-            )
-        })?;
-        fs::copy(&member.manifest_path, &tmp_manifest).with_context(|| {
-            format!(
-                "could not copy manifest file: `{}` to `{}`",
-                member.manifest_path.display(),
+            );
+            println!(
+                "would copy manifest file: `{}` to `{}`",
+                member.manifest_path,
                 tmp_manifest.display()
-            )
-        })?;
+            );
+        } else {
+            fs::create_dir_all(&tmp_package).with_context(|| {
+                format!(
+                    "could not create package directory: `{}`",
+                    tmp_package.display()
+                )
+            })?;
+            fs::copy(&member.manifest_path, &tmp_manifest).with_context(|| {
+                format!(
+                    "could not copy manifest file: `{}` to `{}`",
+                    member.manifest_path,
+                    tmp_manifest.display()
+                )
+            })?;
+        }
 
         for target in member.targets.iter() {
             let relative_src_file = target.src_path.strip_prefix(&package_path).unwrap();
             let tmp_src_file = tmp_package.join(&relative_src_file);
             let tmp_src_dir = tmp_src_file.parent().unwrap();
 
-            fs::create_dir_all(&tmp_src_dir).with_context(|| {
-                format!(
-                    "could not create package's subdirectory: `{}`",
+            if dry_run {
+                println!(
+                    "would create package's subdirectory: `{}`",
                     tmp_src_dir.display()
-                )
-            })?;
-            fs::write(&tmp_src_file, "").with_context(|| {
-                format!("could not create source file: `{}`", tmp_src_file.display())
-            })?;
+                );
+                println!("would create source file: `{}`", tmp_src_file.display());
+            } else {
+                fs::create_dir_all(&tmp_src_dir).with_context(|| {
+                    format!(
+                        "could not create package's subdirectory: `{}`",
+                        tmp_src_dir.display()
+                    )
+                })?;
+                fs::write(&tmp_src_file, stub_source(target)).with_context(|| {
+                    format!("could not create source file: `{}`", tmp_src_file.display())
+                })?;
+            }
         }
     }
 
     Ok(())
 }
+
+/// Returns whether the manifest at `manifest_path` declares a `[workspace]` table, i.e.
+/// whether it is the root of a workspace (virtual or mixed with its own `[package]`) rather
+/// than a standalone package manifest.
+fn manifest_declares_workspace(manifest_path: &Path) -> Result<bool> {
+    let contents = fs::read_to_string(manifest_path).with_context(|| {
+        format!(
+            "could not read manifest file: `{}`",
+            manifest_path.display()
+        )
+    })?;
+
+    Ok(contents
+        .lines()
+        .any(|line| line.trim_start().starts_with("[workspace")))
+}
+
+/// Returns the minimal source code that lets `target` compile on its own.
+///
+/// Targets that cargo expects to produce an executable (`bin`, `example`,
+/// `test`, `bench`, `custom-build`) need at least a `fn main`, otherwise
+/// `rustc` fails before the cached dependencies are ever built. Library-like
+/// targets (`lib`, `rlib`, `cdylib`, `proc-macro`, ...) compile fine from an
+/// empty file.
+fn stub_source(target: &cargo_metadata::Target) -> &'static str {
+    let needs_main = target.kind.iter().any(|kind| {
+        matches!(
+            kind.as_str(),
+            "bin" | "example" | "test" | "bench" | "custom-build"
+        )
+    });
+
+    if needs_main {
+        "fn main() {}\n"
+    } else {
+        ""
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn features_can_be_repeated() {
+        let cli =
+            Cli::from_iter_safe(&["cargo-prepare", "--features", "a", "--features", "b"]).unwrap();
+
+        assert_eq!(cli.features, vec!["a".to_string(), "b".to_string()]);
+    }
+
+    #[test]
+    fn features_rejects_a_second_bare_value() {
+        let cli = Cli::from_iter_safe(&["cargo-prepare", "--features", "a", "b"]).unwrap();
+
+        // `b` is swallowed by `TrailingVarArg` instead of being attached to `--features`, so
+        // it ends up in the pass-through `args` bucket rather than `cli.features`.
+        assert_eq!(cli.features, vec!["a".to_string()]);
+        assert_eq!(cli.args, vec!["b".to_string()]);
+    }
+
+    #[test]
+    fn misrouted_flag_after_a_bare_word_is_rejected() {
+        let cli = Cli::from_iter_safe(&[
+            "cargo-prepare",
+            "--features",
+            "a",
+            "b",
+            "--dest",
+            "/tmp/out",
+        ])
+        .unwrap();
+
+        assert!(check_for_misrouted_flags(&cli.args).is_err());
+    }
+
+    #[test]
+    fn ordinary_passthrough_args_are_accepted() {
+        let cli = Cli::from_iter_safe(&["cargo-prepare", "build", "--release"]).unwrap();
+
+        assert_eq!(cli.args, vec!["build".to_string(), "--release".to_string()]);
+        assert!(check_for_misrouted_flags(&cli.args).is_ok());
+    }
+}